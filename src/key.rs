@@ -1,21 +1,18 @@
 use std::collections::HashMap;
-use std::env;
+use std::{env, fs};
 
 use anyhow::{anyhow, Context, Result};
-use enum_map::{Enum, EnumMap};
 use input_event_codes_hashmap::KEY;
 use quick_xml::events::Event as XmlEvent;
 use quick_xml::Reader as XmlReader;
+use serde::Deserialize;
 
-#[derive(Debug, Enum, PartialEq, Clone, Copy)]
-pub enum Hotkey {
-    SplitKey,
-    ResetKey,
-    SkipKey,
-    UndoKey,
-    PauseKey,
-    ToggleGlobalHotkeys,
-}
+/// Sentinel command that toggles between LiveSplit's `pause` and `resume` commands instead of
+/// sending a fixed one, matching how LiveSplit's own pause hotkey behaves.
+pub const TOGGLE_PAUSE: &str = "togglepause";
+/// Sentinel command that flips `KeyState`'s own enabled/disabled switch rather than being sent to
+/// LiveSplit at all.
+pub const TOGGLE_HOTKEYS: &str = "togglehotkeys";
 
 #[derive(Debug)]
 pub struct Keymapper {
@@ -91,12 +88,12 @@ impl Keymapper {
         }
     }
 
-    pub fn map_combo(&self, combo: &str) -> Result<Vec<u32>> {
+    pub fn map_combo(&self, combo: &str) -> Result<Vec<ComboKey>> {
         let mut vec = Vec::new();
         for key in combo.split(',') {
             let key = key.trim();
             if let Some(code) = self.map(key) {
-                vec.push(code);
+                vec.push(self.to_combo_key(key, code));
             } else {
                 return Err(anyhow!(
                     "Could not find mapping for {} in key combo {}",
@@ -108,11 +105,134 @@ impl Keymapper {
 
         Ok(vec)
     }
+
+    /// Generic modifier names that don't distinguish left/right (as opposed to e.g. `LShiftKey`,
+    /// which does) should match a combo if either physical key is down.
+    const GENERIC_MODIFIERS: &[&str] = &["Control", "ControlKey", "Shift", "ShiftKey", "Alt"];
+
+    /// The other evdev key name in a left/right modifier pair, if `name` is one half of one.
+    fn modifier_pair(name: &str) -> Option<&'static str> {
+        match name {
+            "LEFTCTRL" => Some("RIGHTCTRL"),
+            "LEFTSHIFT" => Some("RIGHTSHIFT"),
+            "LEFTALT" => Some("RIGHTALT"),
+            _ => None,
+        }
+    }
+
+    fn to_combo_key(&self, name: &str, code: u32) -> ComboKey {
+        if Self::GENERIC_MODIFIERS.contains(&name) {
+            if let Some(other) = self
+                .key_map
+                .get(name)
+                .and_then(|mapped| Self::modifier_pair(mapped))
+                .and_then(|other_name| KEY.get(other_name).copied())
+            {
+                return ComboKey::AnyOf(code, other);
+            }
+        }
+
+        ComboKey::Exact(code)
+    }
+}
+
+/// A single requirement within a hotkey combo.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ComboKey {
+    /// Exactly this physical key must be held, e.g. an explicitly-sided modifier like `RShiftKey`.
+    Exact(u32),
+    /// Either physical key satisfies the requirement, e.g. a generic `Control` combo entry is
+    /// satisfied by either the left or the right Ctrl key.
+    AnyOf(u32, u32),
+}
+
+impl ComboKey {
+    fn contains(&self, key: u32) -> bool {
+        match *self {
+            ComboKey::Exact(c) => c == key,
+            ComboKey::AnyOf(a, b) => key == a || key == b,
+        }
+    }
+
+    fn is_down(&self, state: &[bool]) -> bool {
+        match *self {
+            ComboKey::Exact(c) => state[c as usize],
+            ComboKey::AnyOf(a, b) => state[a as usize] || state[b as usize],
+        }
+    }
+
+    /// The physical codes satisfying this requirement that are currently down, so callers can
+    /// suppress exactly the key(s) that were actually pressed rather than every possible one.
+    fn active_codes<'a>(&'a self, state: &'a [bool]) -> impl Iterator<Item = u32> + 'a {
+        let (a, b) = match *self {
+            ComboKey::Exact(c) => (Some(c), None),
+            ComboKey::AnyOf(a, b) => (Some(a), Some(b)),
+        };
+        [a, b].into_iter().flatten().filter(move |c| state[*c as usize])
+    }
+}
+
+/// A single hotkey binding: the combo of key requirements that must be held, and the LiveSplit
+/// server command (or sentinel from [`TOGGLE_PAUSE`]/[`TOGGLE_HOTKEYS`]) it triggers.
+#[derive(Debug, Clone)]
+pub struct Binding {
+    pub combo: Vec<ComboKey>,
+    pub command: String,
+    /// Whether a match should swallow `combo`'s keys in `--grab` mode instead of passing them
+    /// through to the virtual keyboard unchanged. Defaults to on.
+    pub consume: bool,
+    /// A dual-role binding also sends a different command when the (single) key in `combo` is
+    /// held past `Hold::threshold_us` instead of tapped.
+    pub hold: Option<Hold>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Hold {
+    pub command: String,
+    pub threshold_us: i64,
+}
+
+/// Everything a caller needs to resolve a dual-role key press into a tap or hold command.
+#[derive(Debug, Clone)]
+pub struct DualRole {
+    pub tap_command: String,
+    pub hold_command: String,
+    pub threshold_us: i64,
+    /// Whether resolving this key should swallow its raw press/release in `--grab` mode, same as
+    /// `Binding::consume` does for ordinary combo matches.
+    pub consume: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct BindingsFile {
+    #[serde(default, rename = "binding")]
+    bindings: Vec<TomlBinding>,
+}
+
+fn default_hold_threshold_ms() -> u64 {
+    250
+}
+
+fn default_consume() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+struct TomlBinding {
+    combo: String,
+    command: String,
+    /// Whether a match should swallow `combo`'s keys in `--grab` mode. Defaults to on.
+    #[serde(default = "default_consume")]
+    consume: bool,
+    /// Command sent instead of `command` when `combo`'s (single) key is held past the threshold.
+    hold_command: Option<String>,
+    #[serde(default = "default_hold_threshold_ms")]
+    hold_threshold_ms: u64,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum XmlExpect {
-    Hotkey(Hotkey),
+    Binding(&'static str),
     HotkeysEnabled,
     None,
 }
@@ -120,17 +240,64 @@ enum XmlExpect {
 #[derive(Debug)]
 pub struct KeyState {
     state: Vec<bool>,
-    hotkeys: EnumMap<Hotkey, Vec<u32>>,
+    /// Tracks, per raw key code, whether its press was swallowed as part of a matched hotkey
+    /// combo, so the eventual release of that key is swallowed too instead of leaking alone.
+    suppressed: Vec<bool>,
+    bindings: Vec<Binding>,
     hotkeys_enabled: bool,
 }
 
 impl KeyState {
-    pub fn new(settings_path: Option<&str>, profile: &str) -> Result<Self> {
-        let mut hotkeys = EnumMap::default();
+    /// Builds a `KeyState` from a TOML bindings file if `config_path` is given, otherwise falls
+    /// back to the LiveSplit-derived defaults read from `settings_path` (or LiveSplit's own
+    /// settings.cfg location).
+    pub fn new(settings_path: Option<&str>, profile: &str, config_path: Option<&str>) -> Result<Self> {
         let mapper = Keymapper::new();
+
+        let (bindings, hotkeys_enabled) = match config_path {
+            Some(path) => (Self::load_toml_bindings(path, &mapper)?, true),
+            None => Self::load_xml_bindings(settings_path, profile, &mapper)?,
+        };
+
+        let num_keys = KEY.iter().map(|(_, code)| *code).max().unwrap() as usize;
+
+        Ok(Self {
+            state: vec![false; num_keys],
+            suppressed: vec![false; num_keys],
+            bindings,
+            hotkeys_enabled,
+        })
+    }
+
+    fn load_toml_bindings(path: &str, mapper: &Keymapper) -> Result<Vec<Binding>> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+        let file: BindingsFile =
+            toml::from_str(&contents).with_context(|| format!("Failed to parse {}", path))?;
+
+        file.bindings
+            .into_iter()
+            .map(|binding| {
+                Ok(Binding {
+                    combo: mapper.map_combo(&binding.combo)?,
+                    command: binding.command,
+                    consume: binding.consume,
+                    hold: binding.hold_command.map(|command| Hold {
+                        command,
+                        threshold_us: binding.hold_threshold_ms as i64 * 1_000,
+                    }),
+                })
+            })
+            .collect()
+    }
+
+    fn load_xml_bindings(
+        settings_path: Option<&str>,
+        profile: &str,
+        mapper: &Keymapper,
+    ) -> Result<(Vec<Binding>, bool)> {
         let profile_bytes = profile.as_bytes();
 
-        // read LiveSplit settings
         let mut reader = match settings_path {
             Some(s) => XmlReader::from_file(s),
             None => XmlReader::from_file(env::var("HOME")? + "/LiveSplit/settings.cfg"),
@@ -141,6 +308,8 @@ impl KeyState {
         let mut hotkeys_enabled = true;
         let mut buf = Vec::new();
         let mut in_profile = false;
+        let mut combos: HashMap<&'static str, Vec<ComboKey>> = HashMap::new();
+
         loop {
             match reader.read_event_into(&mut buf)? {
                 XmlEvent::Start(e) => {
@@ -157,26 +326,26 @@ impl KeyState {
                             }
                             XmlExpect::None
                         }
-                        b"SplitKey" if in_profile => XmlExpect::Hotkey(Hotkey::SplitKey),
-                        b"ResetKey" if in_profile => XmlExpect::Hotkey(Hotkey::ResetKey),
-                        b"SkipKey" if in_profile => XmlExpect::Hotkey(Hotkey::SkipKey),
-                        b"UndoKey" if in_profile => XmlExpect::Hotkey(Hotkey::UndoKey),
-                        b"PauseKey" if in_profile => XmlExpect::Hotkey(Hotkey::PauseKey),
+                        b"SplitKey" if in_profile => XmlExpect::Binding("startorsplit"),
+                        b"ResetKey" if in_profile => XmlExpect::Binding("reset"),
+                        b"SkipKey" if in_profile => XmlExpect::Binding("skipsplit"),
+                        b"UndoKey" if in_profile => XmlExpect::Binding("unsplit"),
+                        b"PauseKey" if in_profile => XmlExpect::Binding(TOGGLE_PAUSE),
                         b"ToggleGlobalHotkeys" if in_profile => {
-                            XmlExpect::Hotkey(Hotkey::ToggleGlobalHotkeys)
+                            XmlExpect::Binding(TOGGLE_HOTKEYS)
                         }
                         b"GlobalHotkeysEnabled" if in_profile => XmlExpect::HotkeysEnabled,
                         _ => XmlExpect::None,
                     };
                 }
-                XmlEvent::Text(e) => match &expect {
-                    XmlExpect::Hotkey(hotkey) => {
-                        hotkeys[*hotkey] = mapper.map_combo(e.unescape()?.as_ref())?
+                XmlEvent::Text(e) => match expect {
+                    XmlExpect::Binding(command) => {
+                        combos.insert(command, mapper.map_combo(e.unescape()?.as_ref())?);
                     }
                     XmlExpect::HotkeysEnabled => {
                         hotkeys_enabled = e.unescape()?.trim().eq_ignore_ascii_case("true")
                     }
-                    _ => (),
+                    XmlExpect::None => (),
                 },
                 XmlEvent::End(e) => {
                     if e.name().as_ref() == b"HotkeyProfile" {
@@ -189,46 +358,132 @@ impl KeyState {
             }
         }
 
-        let num_keys = KEY.iter().map(|(_, code)| *code).max().unwrap() as usize;
+        let bindings = combos
+            .into_iter()
+            .map(|(command, combo)| Binding {
+                combo,
+                command: command.to_string(),
+                consume: true,
+                hold: None,
+            })
+            .collect();
 
-        Ok(Self {
-            state: vec![false; num_keys],
-            hotkeys,
-            hotkeys_enabled,
+        Ok((bindings, hotkeys_enabled))
+    }
+
+    fn check_hotkey(&self, key: u32, hotkey: &[ComboKey]) -> bool {
+        hotkey.iter().any(|c| c.contains(key)) && hotkey.iter().all(|c| c.is_down(&self.state))
+    }
+
+    /// Whether hotkeys are currently enabled. Dual-role tap/hold resolution uses this to gate its
+    /// commands the same way `handle_key` gates ordinary combo matches.
+    pub fn hotkeys_enabled(&self) -> bool {
+        self.hotkeys_enabled
+    }
+
+    /// Whether `key` participates in any configured hotkey combo. Grab mode uses this to decide
+    /// which keys need to be buffered pending a match instead of passed straight through.
+    pub fn is_combo_key(&self, key: u32) -> bool {
+        self.bindings
+            .iter()
+            .any(|b| b.combo.iter().any(|c| c.contains(key)))
+    }
+
+    /// If `key` is, on its own, bound to a dual-role tap/hold binding, returns the info needed to
+    /// resolve a press of it into a command once the caller knows how long it was held.
+    pub fn dual_role(&self, key: u32) -> Option<DualRole> {
+        self.bindings.iter().find_map(|b| match b.combo.as_slice() {
+            [only] if only.contains(key) => b.hold.as_ref().map(|hold| DualRole {
+                tap_command: b.command.clone(),
+                hold_command: hold.command.clone(),
+                threshold_us: hold.threshold_us,
+                consume: b.consume,
+            }),
+            _ => None,
         })
     }
 
-    fn check_hotkey(&self, key: u32, hotkey: &[u32]) -> bool {
-        hotkey.iter().any(|c| *c == key) && hotkey.iter().all(|c| self.state[*c as usize])
+    /// Marks the physical codes in `combo` that are currently down as suppressed, recording them
+    /// in `consumed`. Shared by the active-combo and toggle-hotkeys paths in [`Self::handle_key`].
+    fn suppress_combo(
+        suppressed: &mut [bool],
+        state: &[bool],
+        combo: &[ComboKey],
+        consumed: &mut Vec<u32>,
+    ) {
+        for combo_key in combo {
+            for code in combo_key.active_codes(state) {
+                if !suppressed[code as usize] {
+                    suppressed[code as usize] = true;
+                    consumed.push(code);
+                }
+            }
+        }
     }
 
-    pub fn handle_key(&mut self, key: u32, is_pressed: bool) -> EnumMap<Hotkey, bool> {
-        let mut result = EnumMap::default();
+    /// Records `key`'s press/release state directly, without matching it against any combo.
+    /// Used for dual-role keys, whose own press/release is resolved separately by the caller but
+    /// which still needs to show up as held for any other combo that uses it as a modifier.
+    pub fn set_state(&mut self, key: u32, is_pressed: bool) {
         self.state[key as usize] = is_pressed;
+    }
+
+    /// Marks `key` as already resolved, so that its eventual release (whenever it comes) is
+    /// swallowed by `handle_key` instead of being matched against any combo. Used when a dual-role
+    /// key's press is resolved as a hold before the key is physically released.
+    pub fn mark_suppressed(&mut self, key: u32) {
+        self.suppressed[key as usize] = true;
+    }
 
-        for (hotkey, combo) in &self.hotkeys {
-            let is_active = self.check_hotkey(key, combo);
-            if is_active && hotkey == Hotkey::ToggleGlobalHotkeys {
+    /// Updates key state for `key` and returns the commands it activated along with the raw codes
+    /// that were consumed as part of a match (on press) or whose matched press is now being
+    /// released (on release). In grab mode, callers swallow consumed codes instead of re-emitting
+    /// them through the virtual keyboard.
+    pub fn handle_key(&mut self, key: u32, is_pressed: bool) -> (Vec<String>, Vec<u32>) {
+        let mut matched = Vec::new();
+        let mut consumed = Vec::new();
+        self.state[key as usize] = is_pressed;
+
+        if !is_pressed && self.suppressed[key as usize] {
+            self.suppressed[key as usize] = false;
+            consumed.push(key);
+        }
+
+        for binding in &self.bindings {
+            let is_active = self.check_hotkey(key, &binding.combo);
+
+            if is_active && binding.command == TOGGLE_HOTKEYS {
                 self.hotkeys_enabled = !self.hotkeys_enabled;
+                if binding.consume {
+                    Self::suppress_combo(&mut self.suppressed, &self.state, &binding.combo, &mut consumed);
+                }
                 if !self.hotkeys_enabled {
-                    return EnumMap::default();
+                    return (Vec::new(), consumed);
                 }
+                continue;
             }
 
+            // Hotkeys are disabled: don't suppress or match ordinary bindings, so their keys pass
+            // straight through to the game instead of being silently swallowed.
             if !self.hotkeys_enabled {
                 continue;
             }
 
-            result[hotkey] = is_active;
+            if is_active {
+                if binding.consume {
+                    Self::suppress_combo(&mut self.suppressed, &self.state, &binding.combo, &mut consumed);
+                }
+                matched.push(binding.command.clone());
+            }
         }
 
-        result
+        (matched, consumed)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::key::Keymapper;
+    use super::{env, fs, Binding, ComboKey, Hold, KeyState, Keymapper, TOGGLE_HOTKEYS};
     use input_event_codes_hashmap::KEY;
 
     #[test]
@@ -244,11 +499,177 @@ mod tests {
         let mapper = Keymapper::new();
         assert_eq!(
             mapper.map_combo("G, Control").unwrap()[..],
-            [KEY["G"], KEY["LEFTCTRL"]]
+            [
+                ComboKey::Exact(KEY["G"]),
+                ComboKey::AnyOf(KEY["LEFTCTRL"], KEY["RIGHTCTRL"])
+            ]
         );
         assert_eq!(
             mapper.map_combo("R, Shift, Alt").unwrap()[..],
-            [KEY["R"], KEY["LEFTSHIFT"], KEY["LEFTALT"]]
+            [
+                ComboKey::Exact(KEY["R"]),
+                ComboKey::AnyOf(KEY["LEFTSHIFT"], KEY["RIGHTSHIFT"]),
+                ComboKey::AnyOf(KEY["LEFTALT"], KEY["RIGHTALT"])
+            ]
+        );
+        assert_eq!(
+            mapper.map_combo("RShiftKey").unwrap()[..],
+            [ComboKey::Exact(KEY["RIGHTSHIFT"])]
         );
     }
+
+    #[test]
+    fn test_load_toml_bindings() {
+        let mapper = Keymapper::new();
+        let toml = r#"
+            [[binding]]
+            combo = "G, Control"
+            command = "startorsplit"
+
+            [[binding]]
+            combo = "Space"
+            command = "tap"
+            consume = false
+            hold_command = "hold"
+            hold_threshold_ms = 300
+        "#;
+
+        let mut path = env::temp_dir();
+        path.push(format!(
+            "live-split-hotkeys-test-{}-{}.toml",
+            std::process::id(),
+            line!()
+        ));
+        fs::write(&path, toml).unwrap();
+        let result = KeyState::load_toml_bindings(path.to_str().unwrap(), &mapper);
+        fs::remove_file(&path).unwrap();
+        let bindings = result.unwrap();
+
+        assert_eq!(bindings.len(), 2);
+
+        assert_eq!(
+            bindings[0].combo,
+            vec![
+                ComboKey::Exact(KEY["G"]),
+                ComboKey::AnyOf(KEY["LEFTCTRL"], KEY["RIGHTCTRL"])
+            ]
+        );
+        assert_eq!(bindings[0].command, "startorsplit");
+        assert!(bindings[0].consume);
+        assert!(bindings[0].hold.is_none());
+
+        assert_eq!(bindings[1].command, "tap");
+        assert!(!bindings[1].consume);
+        let hold = bindings[1].hold.as_ref().unwrap();
+        assert_eq!(hold.command, "hold");
+        assert_eq!(hold.threshold_us, 300_000);
+    }
+
+    /// Builds a `KeyState` directly from `bindings`, without going through a settings file.
+    fn test_key_state(bindings: Vec<Binding>) -> KeyState {
+        let num_keys = KEY.iter().map(|(_, code)| *code).max().unwrap() as usize;
+        KeyState {
+            state: vec![false; num_keys],
+            suppressed: vec![false; num_keys],
+            bindings,
+            hotkeys_enabled: true,
+        }
+    }
+
+    #[test]
+    fn test_handle_key_matches_combo_and_suppresses_its_keys() {
+        let mapper = Keymapper::new();
+        let g = KEY["G"];
+        let lctrl = KEY["LEFTCTRL"];
+        let rctrl = KEY["RIGHTCTRL"];
+        let mut state = test_key_state(vec![Binding {
+            combo: mapper.map_combo("G, Control").unwrap(),
+            command: "startorsplit".to_string(),
+            consume: true,
+            hold: None,
+        }]);
+
+        let (matched, consumed) = state.handle_key(rctrl, true);
+        assert!(matched.is_empty());
+        assert!(consumed.is_empty());
+
+        let (matched, consumed) = state.handle_key(g, true);
+        assert_eq!(matched, vec!["startorsplit".to_string()]);
+        assert_eq!(consumed, vec![g, rctrl]);
+
+        assert!(state.is_combo_key(g));
+        assert!(state.is_combo_key(lctrl));
+        assert!(!state.is_combo_key(KEY["A"]));
+
+        // The release of a suppressed key is swallowed too, instead of leaking alone.
+        let (matched, consumed) = state.handle_key(g, false);
+        assert!(matched.is_empty());
+        assert_eq!(consumed, vec![g]);
+    }
+
+    #[test]
+    fn test_handle_key_non_consuming_binding_does_not_suppress() {
+        let mapper = Keymapper::new();
+        let g = KEY["G"];
+        let mut state = test_key_state(vec![Binding {
+            combo: mapper.map_combo("G").unwrap(),
+            command: "startorsplit".to_string(),
+            consume: false,
+            hold: None,
+        }]);
+
+        let (matched, consumed) = state.handle_key(g, true);
+        assert_eq!(matched, vec!["startorsplit".to_string()]);
+        assert!(consumed.is_empty());
+    }
+
+    #[test]
+    fn test_handle_key_disabled_hotkeys_passes_through_without_suppressing() {
+        let mapper = Keymapper::new();
+        let esc = KEY["ESC"];
+        let g = KEY["G"];
+        let mut state = test_key_state(vec![
+            Binding {
+                combo: mapper.map_combo("Escape").unwrap(),
+                command: TOGGLE_HOTKEYS.to_string(),
+                consume: true,
+                hold: None,
+            },
+            Binding {
+                combo: mapper.map_combo("G").unwrap(),
+                command: "startorsplit".to_string(),
+                consume: true,
+                hold: None,
+            },
+        ]);
+
+        state.handle_key(esc, true);
+        state.handle_key(esc, false);
+        assert!(!state.hotkeys_enabled());
+
+        let (matched, consumed) = state.handle_key(g, true);
+        assert!(matched.is_empty());
+        assert!(consumed.is_empty());
+    }
+
+    #[test]
+    fn test_dual_role_carries_consume() {
+        let mapper = Keymapper::new();
+        let space = KEY["SPACE"];
+        let state = test_key_state(vec![Binding {
+            combo: mapper.map_combo("Space").unwrap(),
+            command: "tap".to_string(),
+            consume: false,
+            hold: Some(Hold {
+                command: "hold".to_string(),
+                threshold_us: 300_000,
+            }),
+        }]);
+
+        let dual = state.dual_role(space).unwrap();
+        assert_eq!(dual.tap_command, "tap");
+        assert_eq!(dual.hold_command, "hold");
+        assert_eq!(dual.threshold_us, 300_000);
+        assert!(!dual.consume);
+    }
 }
@@ -1,22 +1,31 @@
+use std::collections::{HashMap, HashSet};
 use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
 use std::ptr::addr_of;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
 use async_std::channel::{unbounded, Receiver, Sender};
-use async_std::fs::{read_dir, File};
+use async_std::fs::File;
+use async_std::future::timeout;
 use async_std::io::{ReadExt, WriteExt};
 use async_std::net::TcpStream;
 use async_std::path::PathBuf;
-use async_std::prelude::StreamExt;
-use async_std::task;
+use async_std::task::{self, JoinHandle};
 use clap::Parser;
 use futures::future;
+use futures::FutureExt;
 use input_event_codes_hashmap::EV;
-use libc::input_event;
+use libc::{input_event, timeval};
 
 mod key;
+mod uinput;
+mod watch;
 use key::*;
 
+// linux/input.h EVIOCGRAB; not exposed by the libc crate.
+const EVIOCGRAB: libc::c_ulong = 0x4004_4590;
+
 /// Listen for LiveSplit hotkeys
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -27,6 +36,10 @@ struct Args {
     /// Name of the hotkey profile to use
     #[arg(short = 'f', long, default_value_t = String::from("Default"))]
     profile: String,
+    /// Path to a TOML file with custom hotkey-to-command bindings. When given, this replaces the
+    /// bindings read from LiveSplit's settings.cfg entirely
+    #[arg(short = 'c', long)]
+    config: Option<String>,
     /// Hostname or IP address where the LiveSplit server is running
     #[arg(short = 'o', long, default_value_t = String::from("localhost"))]
     host: String,
@@ -36,11 +49,48 @@ struct Args {
     /// Path to the keyboard device file(s) to read from
     #[arg(short, long)]
     devices: Vec<String>,
+    /// Take exclusive ownership of each keyboard device and re-emit everything but matched
+    /// hotkeys through a virtual keyboard, so combos like Ctrl+G don't also reach the game
+    #[arg(short, long)]
+    grab: bool,
     /// Display debug information. Specify twice to show every key event.
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 }
 
+/// A raw event forwarded from `listen_keyboard` to `listen_keys`. `Key` is every non-autorepeat
+/// key press/release, used for hotkey matching. `Other` is everything else (autorepeats, EV_SYN
+/// reports, etc.) and is only sent in `--grab` mode, where it still needs to be re-emitted on the
+/// virtual keyboard for events that aren't swallowed as part of a hotkey.
+#[derive(Debug, Clone, Copy)]
+enum RawEvent {
+    Key {
+        code: u32,
+        pressed: bool,
+        time: timeval,
+    },
+    Other { type_: u16, code: u16, value: i32 },
+}
+
+/// Microseconds between two evdev event timestamps, matching the kernel's timeval precision.
+fn elapsed_micros(press: timeval, release: timeval) -> i64 {
+    (release.tv_sec - press.tv_sec) * 1_000_000 + (release.tv_usec - press.tv_usec)
+}
+
+/// A dual-role key's press, held back from `handle_key` until it's resolved as a tap or a hold
+/// either by its own release, by another key being pressed as a chord, or by `threshold_us`
+/// elapsing with nothing else happening.
+struct PendingTap {
+    code: u32,
+    tap_command: String,
+    hold_command: String,
+    press_time: timeval,
+    threshold_us: i64,
+    deadline: Instant,
+    /// Whether resolving this tap/hold should swallow its raw press/release in `--grab` mode.
+    consume: bool,
+}
+
 struct HotkeyListener {
     args: Args,
     key_state: KeyState,
@@ -48,102 +98,406 @@ struct HotkeyListener {
 
 impl HotkeyListener {
     pub fn new(args: Args) -> Result<Self> {
-        let key_state = KeyState::new(args.settings.as_deref(), args.profile.as_str())?;
+        let key_state = KeyState::new(
+            args.settings.as_deref(),
+            args.profile.as_str(),
+            args.config.as_deref(),
+        )?;
         Ok(Self { args, key_state })
     }
 
-    async fn listen_keyboard(sender: Sender<(u32, bool)>, path: PathBuf) -> Result<()> {
+    async fn listen_keyboard(sender: Sender<RawEvent>, path: PathBuf, grab: bool) -> Result<()> {
         let ev_key = EV["KEY"] as u16;
-        let mut file = File::open(path).await?;
+        let mut file = File::open(&path).await?;
+
+        if grab {
+            // Take exclusive ownership of the device so its events stop reaching the game
+            // directly; listen_keys re-emits what it doesn't swallow through a uinput device.
+            let result = unsafe { libc::ioctl(file.as_raw_fd(), EVIOCGRAB, 1) };
+            if result < 0 {
+                return Err(anyhow!("Failed to grab {:?} (EVIOCGRAB)", path));
+            }
+        }
+
         loop {
-            let (type_, code, value) = {
+            let (type_, code, value, time) = {
                 let mut event_buf = [0u8; size_of::<input_event>()];
                 file.read_exact(&mut event_buf).await?;
                 // I don't think this is that bad because an input_event is ultimately all ints, so there are no invalid
                 // bit patterns, and binrw would just be reading the exact same bytes in the exact same sequence.
                 let event = unsafe { &*(addr_of!(event_buf) as *const input_event) };
-                (event.type_, event.code, event.value)
+                (event.type_, event.code, event.value, event.time)
             };
-            // 2 = autorepeat, which we don't want to listen for
+            // 2 = autorepeat, which we don't want to match hotkeys against
             if type_ == ev_key && value < 2 {
-                let raw_code = code as u32;
-                sender.send((raw_code, value != 0)).await?;
+                sender
+                    .send(RawEvent::Key {
+                        code: code as u32,
+                        pressed: value != 0,
+                        time,
+                    })
+                    .await?;
+            } else if grab {
+                sender.send(RawEvent::Other { type_, code, value }).await?;
             }
         }
     }
 
-    async fn listen_keys(mut self, receiver: Receiver<(u32, bool)>) -> Result<()> {
+    async fn flush_pending(uinput: &mut uinput::VirtualKeyboard, pending: &mut Vec<RawEvent>) -> Result<()> {
+        for event in pending.drain(..) {
+            match event {
+                RawEvent::Key { code, pressed, .. } => {
+                    uinput
+                        .emit(EV["KEY"] as u16, code as u16, pressed as i32)
+                        .await?;
+                }
+                RawEvent::Other { type_, code, value } => {
+                    uinput.emit(type_, code, value).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends `command` to the LiveSplit server, resolving the [`TOGGLE_PAUSE`] sentinel against
+    /// `paused` first.
+    async fn send_command(
+        conn: &mut TcpStream,
+        paused: &mut bool,
+        verbose: u8,
+        command: &str,
+    ) -> Result<()> {
+        let command = if command == TOGGLE_PAUSE {
+            let command = if *paused { "resume" } else { "pause" };
+            *paused = !*paused;
+            command
+        } else {
+            command
+        };
+
+        if verbose > 0 {
+            println!("Sending command {}", command);
+        }
+        conn.write_all(command.as_bytes()).await?;
+        conn.write_all(b"\r\n").await?;
+        Ok(())
+    }
+
+    /// Sends a dual-role tap/hold command, same as `send_command`, but only if hotkeys are
+    /// currently enabled -- dual-role resolution bypasses `KeyState::handle_key`, which is where
+    /// ordinary combo matches get that same gating.
+    async fn send_dual_role_command(
+        key_state: &KeyState,
+        conn: &mut TcpStream,
+        paused: &mut bool,
+        verbose: u8,
+        command: &str,
+    ) -> Result<()> {
+        if key_state.hotkeys_enabled() {
+            Self::send_command(conn, paused, verbose, command).await?;
+        }
+        Ok(())
+    }
+
+    /// Drops any buffered, not-yet-flushed events for `code` and clears it from the undecided set.
+    /// Used once a dual-role key's press has been resolved one way or another, since none of its
+    /// buffered events (or the one that's about to be processed) should reach the virtual keyboard.
+    fn discard_pending(pending: &mut Vec<RawEvent>, held_undecided: &mut HashSet<u32>, code: u32) {
+        pending.retain(|ev| !matches!(ev, RawEvent::Key { code: c, .. } if *c == code));
+        held_undecided.remove(&code);
+    }
+
+    /// Resolves a dual-role key's buffered press once its tap/hold decision has been made. If
+    /// `consume` is true, its buffered events are dropped like `discard_pending`; otherwise they're
+    /// left in `pending` so the key flows through to the virtual keyboard like a non-consuming
+    /// ordinary binding would.
+    fn resolve_pending(
+        pending: &mut Vec<RawEvent>,
+        held_undecided: &mut HashSet<u32>,
+        code: u32,
+        consume: bool,
+    ) {
+        if consume {
+            Self::discard_pending(pending, held_undecided, code);
+        } else {
+            held_undecided.remove(&code);
+        }
+    }
+
+    async fn listen_keys(mut self, receiver: Receiver<RawEvent>) -> Result<()> {
         let mut conn = TcpStream::connect(format!("{}:{}", self.args.host, self.args.port))
             .await
             .context("Could not connect to LiveSplit server")?;
         let mut paused = false;
 
+        let mut uinput = if self.args.grab {
+            Some(uinput::VirtualKeyboard::new().await?)
+        } else {
+            None
+        };
+        // Raw events waiting on a hotkey decision before they can be re-emitted (or dropped).
+        let mut pending: Vec<RawEvent> = Vec::new();
+        // Combo keys currently held whose press hasn't yet been resolved as matched or not.
+        let mut held_undecided: HashSet<u32> = HashSet::new();
+        // A dual-role key's press, held back pending a tap/hold decision. At most one at a time:
+        // while it's pending, no other key can be a fresh dual-role candidate.
+        let mut pending_tap: Option<PendingTap> = None;
+
         loop {
-            let (code, is_pressed) = receiver.recv().await?;
+            let event = if let Some(tap) = &pending_tap {
+                match timeout(
+                    tap.deadline.saturating_duration_since(Instant::now()),
+                    receiver.recv(),
+                )
+                .await
+                {
+                    Ok(event) => event?,
+                    Err(_) => {
+                        // Held past the threshold with nothing else happening: resolve as a hold.
+                        let tap = pending_tap.take().unwrap();
+                        let consume = tap.consume && self.key_state.hotkeys_enabled();
+                        if consume {
+                            self.key_state.mark_suppressed(tap.code);
+                        }
+                        if uinput.is_some() {
+                            Self::resolve_pending(&mut pending, &mut held_undecided, tap.code, consume);
+                        }
+                        Self::send_dual_role_command(
+                            &self.key_state,
+                            &mut conn,
+                            &mut paused,
+                            self.args.verbose,
+                            &tap.hold_command,
+                        )
+                        .await?;
+                        continue;
+                    }
+                }
+            } else {
+                receiver.recv().await?
+            };
+
+            let (code, is_pressed, time) = match event {
+                RawEvent::Key { code, pressed, time } => (code, pressed, time),
+                RawEvent::Other { .. } => {
+                    if let Some(uinput) = uinput.as_mut() {
+                        pending.push(event);
+                        if held_undecided.is_empty() {
+                            Self::flush_pending(uinput, &mut pending).await?;
+                        }
+                    }
+                    continue;
+                }
+            };
+
             if self.args.verbose > 1 {
                 println!("Key {} = {}", code, is_pressed);
             }
-            let active_hotkeys = self.key_state.handle_key(code, is_pressed);
 
-            for hotkey in active_hotkeys
-                .into_iter()
-                .filter_map(|(hotkey, is_active)| is_active.then_some(hotkey))
-            {
-                if self.args.verbose > 0 {
-                    println!("Sending hotkey {:?}", hotkey);
+            if let Some(tap) = pending_tap.take() {
+                if code == tap.code && !is_pressed {
+                    // The dual-role key was released before anything else resolved it: decide tap
+                    // vs hold from how long it was actually held.
+                    let elapsed_us = elapsed_micros(tap.press_time, time);
+                    let command = if elapsed_us < tap.threshold_us {
+                        &tap.tap_command
+                    } else {
+                        &tap.hold_command
+                    };
+                    self.key_state.set_state(code, false);
+                    let consume = tap.consume && self.key_state.hotkeys_enabled();
+                    if uinput.is_some() {
+                        if consume {
+                            Self::discard_pending(&mut pending, &mut held_undecided, code);
+                        } else {
+                            // This release was never buffered (unlike the press), since we didn't
+                            // yet know whether it should reach the game: add it now so it flows
+                            // through like the rest of a non-consuming binding's key events.
+                            pending.push(event);
+                            held_undecided.remove(&code);
+                        }
+                    }
+                    Self::send_dual_role_command(
+                        &self.key_state,
+                        &mut conn,
+                        &mut paused,
+                        self.args.verbose,
+                        command,
+                    )
+                    .await?;
+                    continue;
+                } else if code != tap.code && is_pressed {
+                    // Another key came down while the dual-role key was still held: treat it as a
+                    // chord and resolve the dual-role key as a hold immediately, then fall through
+                    // to process this press normally.
+                    let consume = tap.consume && self.key_state.hotkeys_enabled();
+                    if consume {
+                        self.key_state.mark_suppressed(tap.code);
+                    }
+                    if uinput.is_some() {
+                        Self::resolve_pending(&mut pending, &mut held_undecided, tap.code, consume);
+                    }
+                    Self::send_dual_role_command(
+                        &self.key_state,
+                        &mut conn,
+                        &mut paused,
+                        self.args.verbose,
+                        &tap.hold_command,
+                    )
+                    .await?;
+                } else {
+                    pending_tap = Some(tap);
+                }
+            }
+
+            if pending_tap.is_none() && is_pressed {
+                if let Some(dual) = self.key_state.dual_role(code) {
+                    self.key_state.set_state(code, true);
+                    if uinput.is_some() {
+                        pending.push(event);
+                        held_undecided.insert(code);
+                    }
+                    pending_tap = Some(PendingTap {
+                        code,
+                        tap_command: dual.tap_command,
+                        hold_command: dual.hold_command,
+                        press_time: time,
+                        threshold_us: dual.threshold_us,
+                        deadline: Instant::now() + Duration::from_micros(dual.threshold_us.max(0) as u64),
+                        consume: dual.consume,
+                    });
+                    continue;
+                }
+            }
+
+            if uinput.is_some() {
+                pending.push(event);
+                if self.key_state.is_combo_key(code) {
+                    if is_pressed {
+                        held_undecided.insert(code);
+                    } else {
+                        held_undecided.remove(&code);
+                    }
                 }
-                let command: &'static [u8] = match hotkey {
-                    Hotkey::SplitKey => b"startorsplit\r\n",
-                    Hotkey::ResetKey => b"reset\r\n",
-                    Hotkey::SkipKey => b"skipsplit\r\n",
-                    Hotkey::UndoKey => b"unsplit\r\n",
-                    Hotkey::PauseKey => {
-                        let command: &'static [u8] =
-                            if paused { b"resume\r\n" } else { b"pause\r\n" };
-                        paused = !paused;
-                        command
+            }
+
+            let (matched_commands, consumed) = self.key_state.handle_key(code, is_pressed);
+
+            if let Some(uinput) = uinput.as_mut() {
+                if !consumed.is_empty() {
+                    pending.retain(
+                        |ev| !matches!(ev, RawEvent::Key { code, .. } if consumed.contains(code)),
+                    );
+                    for code in &consumed {
+                        held_undecided.remove(code);
                     }
-                    _ => continue,
-                };
+                }
+                if held_undecided.is_empty() {
+                    Self::flush_pending(uinput, &mut pending).await?;
+                }
+            }
 
-                conn.write_all(command).await?;
+            for command in matched_commands {
+                Self::send_command(&mut conn, &mut paused, self.args.verbose, &command).await?;
             }
         }
     }
 
     pub async fn listen(self) -> Result<()> {
-        // find keyboards
-        let devices = if !self.args.devices.is_empty() {
-            self.args.devices.iter().map(PathBuf::from).collect()
-        } else {
-            let mut devices = Vec::new();
-            let mut entries = read_dir("/dev/input/by-path/").await?;
-            while let Some(entry) = entries.next().await {
-                let path = entry?.path();
-                if path
-                    .file_name()
-                    .map_or(false, |n| n.to_string_lossy().ends_with("-event-kbd"))
-                {
-                    devices.push(path);
+        let (sender, receiver) = unbounded();
+        let grab = self.args.grab;
+        let verbose = self.args.verbose;
+
+        // If the user pinned specific device paths, there's nothing to hot-plug: read exactly
+        // those and run until one of them (or the LiveSplit connection) errors out.
+        if !self.args.devices.is_empty() {
+            let devices: Vec<_> = self.args.devices.iter().map(PathBuf::from).collect();
+            if verbose > 0 {
+                println!("Keyboards: {:?}", devices);
+            }
+            let mut tasks: Vec<_> = devices
+                .into_iter()
+                .map(|d| task::spawn(Self::listen_keyboard(sender.clone(), d, grab)))
+                .collect();
+            tasks.push(task::spawn(self.listen_keys(receiver)));
+            return future::try_join_all(tasks).await.map(|_| ());
+        }
+
+        // Otherwise, keep reconciling the set of keyboards against /dev/input/by-path so
+        // devices plugged in (or re-enumerated on wake) after startup are picked up, and
+        // devices that disappear have their listener task cancelled.
+        let mut device_tasks: HashMap<PathBuf, JoinHandle<Result<()>>> = HashMap::new();
+        for path in watch::scan_keyboards().await? {
+            if verbose > 0 {
+                println!("Keyboard found: {:?}", path);
+            }
+            device_tasks.insert(
+                path.clone(),
+                task::spawn(Self::listen_keyboard(sender.clone(), path, grab)),
+            );
+        }
+
+        let changes = watch::watch_keyboards()?;
+        let listen_keys_task = task::spawn(self.listen_keys(receiver)).fuse();
+        futures::pin_mut!(listen_keys_task);
+
+        // Once the watcher task dies, its sender is dropped and recv() would resolve with Err
+        // immediately forever; polling it past that point would spin the select loop with no
+        // backoff. Once that happens, stop polling it and fall back to running with whatever
+        // keyboards are already tracked, without further hot-plug detection.
+        let mut watcher_alive = true;
+
+        let result = loop {
+            futures::select! {
+                result = listen_keys_task => break result,
+                change = async {
+                    if watcher_alive {
+                        changes.recv().await
+                    } else {
+                        future::pending().await
+                    }
+                }.fuse() => {
+                    if change.is_err() {
+                        if verbose > 0 {
+                            println!("Keyboard hot-plug watcher died; continuing without hot-plug detection");
+                        }
+                        watcher_alive = false;
+                        continue;
+                    }
+
+                    let current = watch::scan_keyboards().await?;
+
+                    for path in &current {
+                        device_tasks.entry(path.clone()).or_insert_with(|| {
+                            if verbose > 0 {
+                                println!("Keyboard added: {:?}", path);
+                            }
+                            task::spawn(Self::listen_keyboard(sender.clone(), path.clone(), grab))
+                        });
+                    }
+
+                    let removed: Vec<PathBuf> = device_tasks
+                        .keys()
+                        .filter(|path| !current.contains(*path))
+                        .cloned()
+                        .collect();
+                    for path in removed {
+                        if let Some(handle) = device_tasks.remove(&path) {
+                            if verbose > 0 {
+                                println!("Keyboard removed: {:?}", path);
+                            }
+                            handle.cancel().await;
+                        }
+                    }
                 }
             }
-            devices
         };
 
-        if devices.is_empty() {
-            return Err(anyhow!("No keyboard devices found"));
+        for (_, handle) in device_tasks {
+            handle.cancel().await;
         }
 
-        if self.args.verbose > 0 {
-            println!("Keyboards: {:?}", devices);
-        }
-        let (sender, receiver) = unbounded();
-        let mut tasks: Vec<_> = devices
-            .into_iter()
-            .map(|d| task::spawn(Self::listen_keyboard(sender.clone(), d)))
-            .collect();
-        tasks.push(task::spawn(self.listen_keys(receiver)));
-        future::try_join_all(tasks).await.map(|_| ())
+        result
     }
 }
 
@@ -152,3 +506,34 @@ async fn main() -> Result<()> {
     let listener = HotkeyListener::new(Args::parse())?;
     listener.listen().await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_elapsed_micros() {
+        let press = timeval {
+            tv_sec: 10,
+            tv_usec: 500_000,
+        };
+        let release = timeval {
+            tv_sec: 10,
+            tv_usec: 750_000,
+        };
+        assert_eq!(elapsed_micros(press, release), 250_000);
+    }
+
+    #[test]
+    fn test_elapsed_micros_across_seconds() {
+        let press = timeval {
+            tv_sec: 10,
+            tv_usec: 900_000,
+        };
+        let release = timeval {
+            tv_sec: 11,
+            tv_usec: 100_000,
+        };
+        assert_eq!(elapsed_micros(press, release), 200_000);
+    }
+}
@@ -0,0 +1,128 @@
+use std::ffi::CString;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{anyhow, Context, Result};
+use async_std::fs::OpenOptions;
+use async_std::io::WriteExt;
+use input_event_codes_hashmap::KEY;
+use libc::{input_event, timeval};
+
+const UINPUT_MAX_NAME_SIZE: usize = 80;
+
+const EV_SYN: libc::c_int = 0x00;
+const EV_KEY: libc::c_int = 0x01;
+
+// linux/uinput.h ioctl numbers; not exposed by the libc crate.
+const UI_SET_EVBIT: libc::c_ulong = 0x4004_5564;
+const UI_SET_KEYBIT: libc::c_ulong = 0x4004_5565;
+const UI_DEV_CREATE: libc::c_ulong = 0x5501;
+const UI_DEV_DESTROY: libc::c_ulong = 0x5502;
+
+#[repr(C)]
+struct InputId {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+// linux/uinput.h struct uinput_user_dev, field for field. Only name and id are set; the unused
+// absolute-axis arrays still need to be present so the struct is the size the kernel expects.
+#[repr(C)]
+struct UinputUserDev {
+    name: [u8; UINPUT_MAX_NAME_SIZE],
+    id: InputId,
+    ff_effects_max: u32,
+    absmax: [i32; 64],
+    absmin: [i32; 64],
+    absfuzz: [i32; 64],
+    absflat: [i32; 64],
+}
+
+/// A virtual keyboard created through `/dev/uinput`, used to re-emit the events read from a
+/// grabbed keyboard that weren't consumed by a matched hotkey.
+pub struct VirtualKeyboard {
+    file: async_std::fs::File,
+}
+
+impl VirtualKeyboard {
+    pub async fn new() -> Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open("/dev/uinput")
+            .await
+            .context("Failed to open /dev/uinput; is the uinput module loaded?")?;
+        let fd = file.as_raw_fd();
+
+        unsafe {
+            if libc::ioctl(fd, UI_SET_EVBIT, EV_KEY) < 0 {
+                return Err(anyhow!("UI_SET_EVBIT(EV_KEY) failed"));
+            }
+            if libc::ioctl(fd, UI_SET_EVBIT, EV_SYN) < 0 {
+                return Err(anyhow!("UI_SET_EVBIT(EV_SYN) failed"));
+            }
+            for code in KEY.values() {
+                if libc::ioctl(fd, UI_SET_KEYBIT, *code as libc::c_int) < 0 {
+                    return Err(anyhow!("UI_SET_KEYBIT({}) failed", code));
+                }
+            }
+
+            let mut dev: UinputUserDev = std::mem::zeroed();
+            let name = CString::new("live-split-hotkeys").unwrap();
+            let name_bytes = name.as_bytes_with_nul();
+            dev.name[..name_bytes.len()].copy_from_slice(name_bytes);
+            dev.id.bustype = 0x03; // BUS_USB
+            dev.id.vendor = 0x1234;
+            dev.id.product = 0x5678;
+            dev.id.version = 1;
+
+            let dev_bytes = std::slice::from_raw_parts(
+                &dev as *const UinputUserDev as *const u8,
+                size_of::<UinputUserDev>(),
+            );
+            if libc::write(fd, dev_bytes.as_ptr() as *const libc::c_void, dev_bytes.len()) < 0 {
+                return Err(anyhow!("Failed to write uinput device descriptor"));
+            }
+
+            if libc::ioctl(fd, UI_DEV_CREATE) < 0 {
+                return Err(anyhow!("UI_DEV_CREATE failed"));
+            }
+        }
+
+        Ok(Self { file })
+    }
+
+    /// Re-emits a raw event exactly as it was read from the grabbed device.
+    pub async fn emit(&mut self, type_: u16, code: u16, value: i32) -> Result<()> {
+        let event = input_event {
+            time: timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_,
+            code,
+            value,
+        };
+        // Same reasoning as the read side in main.rs: an input_event is all ints, so there's no
+        // invalid bit pattern to worry about when writing it out byte-for-byte.
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &event as *const input_event as *const u8,
+                size_of::<input_event>(),
+            )
+        };
+        self.file
+            .write_all(bytes)
+            .await
+            .context("Failed to write event to uinput device")
+    }
+}
+
+impl Drop for VirtualKeyboard {
+    fn drop(&mut self) {
+        unsafe {
+            libc::ioctl(self.file.as_raw_fd(), UI_DEV_DESTROY);
+        }
+    }
+}
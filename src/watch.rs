@@ -0,0 +1,76 @@
+use std::collections::HashSet;
+use std::io::ErrorKind;
+
+use anyhow::{Context, Result};
+use async_std::channel::{unbounded, Receiver};
+use async_std::fs::read_dir;
+use async_std::path::PathBuf;
+use async_std::prelude::StreamExt;
+use async_std::task;
+use inotify::{Inotify, WatchMask};
+
+const BY_PATH_DIR: &str = "/dev/input/by-path";
+const INPUT_DIR: &str = "/dev/input";
+
+fn is_keyboard_node(name: &str) -> bool {
+    name.ends_with("-event-kbd")
+}
+
+/// Scan `/dev/input/by-path/` for the keyboard device nodes currently present. `by-path` doesn't
+/// exist until udev has populated it at least once, so a missing directory just means no devices
+/// are there yet rather than an error.
+pub async fn scan_keyboards() -> Result<HashSet<PathBuf>> {
+    let mut devices = HashSet::new();
+    let mut entries = match read_dir(BY_PATH_DIR).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(devices),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next().await {
+        let path = entry?.path();
+        if path
+            .file_name()
+            .map_or(false, |n| is_keyboard_node(&n.to_string_lossy()))
+        {
+            devices.insert(path);
+        }
+    }
+    Ok(devices)
+}
+
+/// Watch `/dev/input` and `/dev/input/by-path` for nodes being created or removed, sending a
+/// notification each time something changes. The receiver doesn't learn *what* changed; callers
+/// are expected to re-scan with [`scan_keyboards`] and diff against what they already know about.
+pub fn watch_keyboards() -> Result<Receiver<()>> {
+    let mut inotify = Inotify::init().context("Failed to initialize inotify")?;
+    match inotify.watches().add(
+        BY_PATH_DIR,
+        WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVED_FROM | WatchMask::MOVED_TO,
+    ) {
+        Ok(_) => (),
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(e).context("Failed to watch /dev/input/by-path"),
+    }
+    // by-path doesn't exist until udev has populated it at least once; also watch /dev/input
+    // itself so we notice it (or the event nodes directly inside it) coming and going.
+    inotify
+        .watches()
+        .add(INPUT_DIR, WatchMask::CREATE | WatchMask::DELETE)
+        .context("Failed to watch /dev/input")?;
+
+    let (sender, receiver) = unbounded();
+    task::spawn_blocking(move || -> Result<()> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            let events = inotify
+                .read_events_blocking(&mut buffer)
+                .context("Failed to read inotify events")?;
+            if events.count() > 0 && sender.send_blocking(()).is_err() {
+                // Receiver dropped; nothing left to notify.
+                return Ok(());
+            }
+        }
+    });
+
+    Ok(receiver)
+}